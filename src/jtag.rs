@@ -0,0 +1,259 @@
+//! JTAG TAP state machine and bit-level clock encoding.
+//!
+//! This is the primitive layer that `CH34x::shift_ir`/`shift_dr` build on: it
+//! knows how to get the TAP from one IEEE 1149.1 state to another and how to
+//! turn a bit-serial IR/DR scan into the two-byte clock pairs the CH347
+//! understands, but it has no notion of USB at all.
+
+use std::collections::VecDeque;
+
+/// The 16 states of the IEEE 1149.1 TAP controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JtagState {
+    TestLogicReset,
+    RunTestIdle,
+    SelectDrScan,
+    CaptureDr,
+    ShiftDr,
+    Exit1Dr,
+    PauseDr,
+    Exit2Dr,
+    UpdateDr,
+    SelectIrScan,
+    CaptureIr,
+    ShiftIr,
+    Exit1Ir,
+    PauseIr,
+    Exit2Ir,
+    UpdateIr,
+}
+
+impl JtagState {
+    const ALL: [JtagState; 16] = [
+        JtagState::TestLogicReset,
+        JtagState::RunTestIdle,
+        JtagState::SelectDrScan,
+        JtagState::CaptureDr,
+        JtagState::ShiftDr,
+        JtagState::Exit1Dr,
+        JtagState::PauseDr,
+        JtagState::Exit2Dr,
+        JtagState::UpdateDr,
+        JtagState::SelectIrScan,
+        JtagState::CaptureIr,
+        JtagState::ShiftIr,
+        JtagState::Exit1Ir,
+        JtagState::PauseIr,
+        JtagState::Exit2Ir,
+        JtagState::UpdateIr,
+    ];
+
+    /// `(next state on tms=0, next state on tms=1)`, per the IEEE 1149.1 TAP diagram.
+    fn transitions(self) -> (Self, Self) {
+        use JtagState::*;
+        match self {
+            TestLogicReset => (RunTestIdle, TestLogicReset),
+            RunTestIdle => (RunTestIdle, SelectDrScan),
+            SelectDrScan => (CaptureDr, SelectIrScan),
+            CaptureDr => (ShiftDr, Exit1Dr),
+            ShiftDr => (ShiftDr, Exit1Dr),
+            Exit1Dr => (PauseDr, UpdateDr),
+            PauseDr => (PauseDr, Exit2Dr),
+            Exit2Dr => (ShiftDr, UpdateDr),
+            UpdateDr => (RunTestIdle, SelectDrScan),
+            SelectIrScan => (CaptureIr, TestLogicReset),
+            CaptureIr => (ShiftIr, Exit1Ir),
+            ShiftIr => (ShiftIr, Exit1Ir),
+            Exit1Ir => (PauseIr, UpdateIr),
+            PauseIr => (PauseIr, Exit2Ir),
+            Exit2Ir => (ShiftIr, UpdateIr),
+            UpdateIr => (RunTestIdle, SelectIrScan),
+        }
+    }
+
+    /// Returns the TMS bit sequence that drives the TAP from `from` to `to`,
+    /// following the shortest path through the state graph.
+    pub(crate) fn navigate(from: JtagState, to: JtagState) -> Vec<bool> {
+        if from == to {
+            return Vec::new();
+        }
+
+        let from_idx = Self::ALL.iter().position(|&s| s == from).unwrap();
+        let to_idx = Self::ALL.iter().position(|&s| s == to).unwrap();
+
+        let mut prev: [Option<(usize, bool)>; 16] = [None; 16];
+        let mut visited = [false; 16];
+        let mut queue = VecDeque::new();
+
+        visited[from_idx] = true;
+        queue.push_back(from_idx);
+
+        while let Some(idx) = queue.pop_front() {
+            if idx == to_idx {
+                break;
+            }
+            let (zero, one) = Self::ALL[idx].transitions();
+            for (tms, next) in [(false, zero), (true, one)] {
+                let next_idx = Self::ALL.iter().position(|&s| s == next).unwrap();
+                if !visited[next_idx] {
+                    visited[next_idx] = true;
+                    prev[next_idx] = Some((idx, tms));
+                    queue.push_back(next_idx);
+                }
+            }
+        }
+
+        let mut path = Vec::new();
+        let mut idx = to_idx;
+        while let Some((p, tms)) = prev[idx] {
+            path.push(tms);
+            idx = p;
+        }
+        path.reverse();
+        path
+    }
+}
+
+/// A single clocked JTAG bit: TDI on bit 4, TMS on bit 1, TCK toggled between
+/// the two bytes of the pair (`Command` value, then `| 0x01`).
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Command {
+    Clock {
+        tms: bool,
+        tdi: bool,
+        trst: bool,
+        srst: bool,
+    },
+    Reset(bool),
+}
+
+impl From<Command> for u8 {
+    fn from(value: Command) -> Self {
+        match value {
+            Command::Reset(x) => u8::from(Command::Clock {
+                tms: true,
+                tdi: true,
+                trst: x,
+                srst: false,
+            }),
+            Command::Clock {
+                tms,
+                tdi,
+                trst,
+                srst,
+            } => {
+                (u8::from(tms) << 1)
+                    | (u8::from(tdi) << 4)
+                    | (u8::from(trst) << 5)
+                    | u8::from(srst) << 6
+                    | 0
+            }
+        }
+    }
+}
+
+impl Command {
+    pub(crate) fn new(tms: bool, tdi: bool) -> Self {
+        Command::Clock {
+            tms,
+            tdi,
+            trst: false,
+            srst: false,
+        }
+    }
+}
+
+pub(crate) struct ClockBuilder {
+    pub(crate) buf: Vec<u8>,
+}
+
+impl ClockBuilder {
+    pub(crate) fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub(crate) fn add(mut self, command: Command) -> Self {
+        let left = u8::from(command);
+        let right = u8::from(left | 0x01);
+        self.buf.push(left);
+        self.buf.push(right);
+        self
+    }
+}
+
+/// Clocks a TMS-only sequence (TDI held low), used to walk the TAP between states.
+pub(crate) fn encode_tms(tms: &[bool]) -> Vec<u8> {
+    tms.iter()
+        .fold(ClockBuilder::new(), |builder, &tms| {
+            builder.add(Command::new(tms, false))
+        })
+        .buf
+}
+
+/// Encodes a bit-serial IR/DR scan, holding TMS=1 on the final bit so the
+/// scan exits straight out of Shift-IR/Shift-DR into Exit1-IR/Exit1-DR.
+pub(crate) fn encode_shift(bits: &[bool]) -> Vec<u8> {
+    let last = bits.len().saturating_sub(1);
+    bits.iter()
+        .enumerate()
+        .fold(ClockBuilder::new(), |builder, (i, &tdi)| {
+            builder.add(Command::new(i == last, tdi))
+        })
+        .buf
+}
+
+/// Decodes TDO out of the bulk-in bytes `send` returned for a scan of `bit_count` bits.
+pub(crate) fn decode_tdo(raw: &[u8], bit_count: usize) -> Vec<bool> {
+    raw.iter().take(bit_count).map(|&b| b & 0x01 != 0).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn navigate_is_a_noop_for_the_same_state() {
+        for &state in &JtagState::ALL {
+            assert!(JtagState::navigate(state, state).is_empty());
+        }
+    }
+
+    #[test]
+    fn navigate_reaches_every_state_from_every_state() {
+        for &from in &JtagState::ALL {
+            for &to in &JtagState::ALL {
+                let path = JtagState::navigate(from, to);
+                let mut state = from;
+                for tms in path {
+                    let (zero, one) = state.transitions();
+                    state = if tms { one } else { zero };
+                }
+                assert_eq!(state, to, "failed to navigate {from:?} -> {to:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn encode_shift_holds_tms_low_until_the_last_bit() {
+        let bits = [true, false, true, true];
+        let encoded = encode_shift(&bits);
+        assert_eq!(encoded.len(), bits.len() * 2);
+
+        for (i, pair) in encoded.chunks(2).enumerate() {
+            let tms = (pair[0] >> 1) & 0x01 != 0;
+            assert_eq!(tms, i == bits.len() - 1);
+        }
+    }
+
+    #[test]
+    fn decode_tdo_reads_bit_zero_of_each_response_byte() {
+        let raw = [0x00, 0x01, 0x01, 0x00];
+        assert_eq!(decode_tdo(&raw, 4), vec![false, true, true, false]);
+    }
+
+    #[test]
+    fn decode_tdo_truncates_to_bit_count() {
+        let raw = [0x01, 0x01, 0x01];
+        assert_eq!(decode_tdo(&raw, 2), vec![true, true]);
+    }
+}
@@ -0,0 +1,615 @@
+mod jtag;
+mod queue;
+mod swd;
+
+use jtag::{ClockBuilder, Command, JtagState};
+use nusb::{DeviceInfo, Interface, transfer::RequestBuffer};
+use probe_rs::probe::{
+    DebugProbe, DebugProbeError, DebugProbeInfo, DebugProbeSelector, ProbeCreationError,
+    ProbeFactory, WireProtocol,
+};
+use queue::TransferScheduler;
+use smol::future::FutureExt;
+use smol::{Timer, block_on};
+use std::io;
+use std::time::Duration;
+use swd::SwdPort;
+const CH34X_VID_PID: [(u16, u16); 3] = [(0x1A86, 0x55DE), (0x1A86, 0x55DD), (0x1A86, 0x55E8)];
+
+pub(crate) fn is_ch34x_device(device: &DeviceInfo) -> bool {
+    CH34X_VID_PID.contains(&(device.vendor_id(), device.product_id()))
+}
+
+#[derive(Debug)]
+pub struct Ch347ProbeFactory;
+
+impl ProbeFactory for Ch347ProbeFactory {
+    fn list_probes(&self) -> Vec<DebugProbeInfo> {
+        let Ok(devices) = nusb::list_devices() else {
+            return Vec::new();
+        };
+
+        devices
+            .filter(is_ch34x_device)
+            .map(|device| {
+                DebugProbeInfo::new(
+                    device.product_string().unwrap_or("ch347").to_string(),
+                    device.vendor_id(),
+                    device.product_id(),
+                    device.serial_number().map(str::to_string),
+                    &Ch347ProbeFactory,
+                    None,
+                )
+            })
+            .collect()
+    }
+
+    fn open(&self, selector: &DebugProbeSelector) -> Result<Box<dyn DebugProbe>, DebugProbeError> {
+        let probe = CH34x::new_from_selector(Some(selector))
+            .map_err(DebugProbeError::ProbeCouldNotBeCreated)?;
+        Ok(Box::new(probe))
+    }
+}
+
+/// A JTAG scan interface, handed out by [`DebugProbe::try_get_interface`] as a
+/// `Box<dyn JtagInterface>` - consuming `self` the same way the real
+/// `probe_rs::probe::DebugProbe::try_get_interface` does, so a failed call
+/// can hand the boxed probe back to the caller instead of stranding it.
+///
+/// This approximates probe-rs's own `JTAGAccess` trait; we can't pin an exact
+/// version of `probe-rs` in this tree (no `Cargo.toml` anywhere in its
+/// history), so `try_get_interface` hands out this crate-local trait instead
+/// of the genuine one until a real dependency is vendored in and this is
+/// checked against its actual shape.
+pub trait JtagInterface: Send {
+    fn shift_ir(&mut self, bits: &[bool]) -> Result<Vec<bool>, DebugProbeError>;
+    fn shift_dr(&mut self, bits: &[bool], read: bool) -> Result<Vec<bool>, DebugProbeError>;
+}
+
+/// An SWD debug interface, handed out by [`DebugProbe::try_get_arm_interface`]
+/// for Cortex-M targets that don't expose JTAG.
+///
+/// Same shape and the same caveat as [`JtagInterface`]: this stands in for
+/// probe-rs's `architecture::arm::ArmDebugInterface` until the real crate is
+/// vendored.
+pub trait ArmDebugInterface: Send {
+    fn raw_read_register(&mut self, port: SwdPort, addr: u8) -> Result<u32, DebugProbeError>;
+    fn raw_write_register(
+        &mut self,
+        port: SwdPort,
+        addr: u8,
+        value: u32,
+    ) -> Result<(), DebugProbeError>;
+}
+
+#[derive(Debug)]
+pub(crate) enum PACK {
+    STANDARD_PACK,
+    LARGER_PACK,
+}
+
+pub struct CH34x {
+    device: Interface,
+    name: String,
+    epout: u8,
+    epin: u8,
+    pack: Option<PACK>,
+    speed_khz: u32,
+    attached: bool,
+    tap_state: JtagState,
+    scheduler: Option<TransferScheduler>,
+    protocol: Option<WireProtocol>,
+}
+
+/// Whether `device` is the one `selector` asked for; a `None` selector matches
+/// the first CH34x device found, which is how the standalone demo picks one.
+fn matches_selector(device: &DeviceInfo, selector: Option<&DebugProbeSelector>) -> bool {
+    let Some(selector) = selector else {
+        return true;
+    };
+
+    device.vendor_id() == selector.vendor_id
+        && device.product_id() == selector.product_id
+        && selector
+            .serial_number
+            .as_deref()
+            .map_or(true, |serial| device.serial_number() == Some(serial))
+}
+
+impl CH34x {
+    pub fn new_from_selector(
+        selector: Option<&DebugProbeSelector>,
+    ) -> Result<Self, ProbeCreationError> {
+        let device = nusb::list_devices()
+            .map_err(ProbeCreationError::Usb)?
+            .filter(is_ch34x_device)
+            .find(|device| matches_selector(device, selector))
+            .ok_or(ProbeCreationError::NotFound)?;
+
+        Self::new_from_device(device)
+    }
+
+    fn new_from_device(device: DeviceInfo) -> Result<Self, ProbeCreationError> {
+        let device_handle = device
+            .open()
+            .map_err(probe_rs::probe::ProbeCreationError::Usb)?;
+
+        let config = device_handle
+            .configurations()
+            .next()
+            .expect("Can get usb device configs");
+
+        log::info!("Active config descriptor: {:?}", config);
+
+        // Walk every interface's descriptor for the vendor-specific (class
+        // 0xFF) one that exposes a bulk-in/bulk-out endpoint pair, rather
+        // than assuming interface 4 and endpoints 0x06/0x86 as before - that
+        // only held for one CH347 variant.
+        let (interface_number, epout, epin) = config
+            .interfaces()
+            .find_map(|interface| {
+                let interface_number = interface.interface_number();
+                let descriptor = interface.alt_settings().next()?;
+
+                if descriptor.class() != 0xFF {
+                    return None;
+                }
+
+                let mut epout = None;
+                let mut epin = None;
+                for endpoint in descriptor.endpoints() {
+                    if endpoint.transfer_type() != nusb::transfer::EndpointType::Bulk {
+                        continue;
+                    }
+                    match endpoint.direction() {
+                        nusb::transfer::Direction::Out => epout = Some(endpoint.address()),
+                        nusb::transfer::Direction::In => epin = Some(endpoint.address()),
+                    }
+                }
+
+                Some((interface_number, epout?, epin?))
+            })
+            .ok_or(ProbeCreationError::NotFound)?;
+
+        let interface = device_handle
+            .claim_interface(interface_number)
+            .map_err(ProbeCreationError::Usb)?;
+
+        Ok(Self {
+            device: interface,
+            name: device.product_string().unwrap_or("ch347").to_string(),
+            epout,
+            epin,
+            pack: None,
+            speed_khz: 0,
+            attached: false,
+            tap_state: JtagState::TestLogicReset,
+            scheduler: None,
+            protocol: None,
+        })
+    }
+
+    pub fn ch347_jtag_init(&mut self) -> Result<(), DebugProbeError> {
+        retry_on_timeout(|| {
+            self.device.write_bulk(
+                self.epout,
+                &[0xD0, 6, 0, 0, 9u8, 0x00, 0x00, 0x00, 0x00],
+                Duration::from_millis(500),
+            )
+        })?;
+
+        let mut rev = vec![0; 4];
+        retry_on_timeout(|| {
+            self.device
+                .read_bulk(self.epin, &mut rev, Duration::from_millis(500))
+        })?;
+
+        if let Some(val) = rev.last() {
+            log::info!("Last value is {}", *val);
+            self.pack = if *val == 0x00 {
+                Some(PACK::STANDARD_PACK)
+            } else {
+                Some(PACK::LARGER_PACK)
+            }
+        }
+
+        self.set_speed(60000)?;
+        self.protocol = Some(WireProtocol::Jtag);
+        Ok(())
+    }
+
+    fn set_speed(&mut self, speed_khz: u32) -> Result<u32, DebugProbeError> {
+        let index = self.speed_khz_index(speed_khz)?;
+        log::info!("Get speed index: {}", index);
+        let buf = [0xD0, 0x06, 0x00, 0x00, index, 0x00, 0x00, 0x00, 0x00];
+        retry_on_timeout(|| {
+            self.device
+                .write_bulk(self.epout, &buf, Duration::from_millis(500))
+        })?;
+
+        let mut rev = vec![0; 4];
+        retry_on_timeout(|| {
+            self.device
+                .read_bulk(self.epin, &mut rev, Duration::from_millis(500))
+        })?;
+        if *rev.last().unwrap_or(&0xFF) != 0x00 {
+            return Err(DebugProbeError::UnsupportedSpeed(speed_khz));
+        }
+        self.speed_khz = speed_khz;
+        Ok(speed_khz)
+    }
+
+    fn send_reset(&mut self, asserted: bool) -> Result<(), DebugProbeError> {
+        let cmd = ClockBuilder::new().add(Command::Reset(asserted));
+        self.send(&cmd.buf)?;
+        Ok(())
+    }
+
+    fn speed_khz_index(&self, speed: u32) -> Result<u8, DebugProbeError> {
+        let index;
+        log::info!(
+            "pack mode: {:?}, seek speed index for {}khz",
+            self.pack,
+            speed
+        );
+        match self.pack {
+            Some(PACK::STANDARD_PACK) => {
+                index = match speed {
+                    1875 => 0,
+                    3750 => 1,
+                    7500 => 2,
+                    15000 => 3,
+                    30000 => 4,
+                    60000 => 5,
+                    _ => return Err(DebugProbeError::UnsupportedSpeed(speed)),
+                };
+            }
+            Some(PACK::LARGER_PACK) => {
+                index = match speed {
+                    468 => 0,
+                    937 => 1,
+                    1875 => 2,
+                    3750 => 3,
+                    7500 => 4,
+                    15000 => 5,
+                    30000 => 6,
+                    60000 => 7,
+                    _ => return Err(DebugProbeError::UnsupportedSpeed(speed)),
+                }
+            }
+            None => {
+                return Err(DebugProbeError::UnsupportedSpeed(speed));
+            }
+        }
+        Ok(index)
+    }
+
+    /// Clocks `buf` through the scan chain and returns the TDO bytes, via the
+    /// same [`TransferScheduler`] framing `shift_ir`/`shift_dr` use - kept
+    /// `pub` for standalone callers (e.g. the demo binary) that want to clock
+    /// a raw command sequence without going through `JtagInterface`.
+    pub fn send(&mut self, buf: &[u8]) -> Result<Vec<u8>, DebugProbeError> {
+        self.queue_commands(buf)?;
+        let rev = self.flush()?;
+        log::info!("rev: {:?}", rev);
+        Ok(rev)
+    }
+
+    /// Lazily spins up the [`TransferScheduler`] for this device; the pack
+    /// mode is only known once `ch347_jtag_init` has run.
+    fn scheduler(&mut self) -> &mut TransferScheduler {
+        self.scheduler.get_or_insert_with(|| {
+            TransferScheduler::new(&self.device, self.epout, self.epin, self.pack.as_ref())
+        })
+    }
+
+    /// Enqueues `buf` on the transfer scheduler; it waits on earlier transfers
+    /// only when the scheduler's in-flight cap is reached.
+    fn queue_commands(&mut self, buf: &[u8]) -> Result<(), DebugProbeError> {
+        self.scheduler().queue_commands(buf)
+    }
+
+    /// Drives every outstanding queued transfer and returns the concatenated TDO bytes.
+    fn flush(&mut self) -> Result<Vec<u8>, DebugProbeError> {
+        self.scheduler().flush()
+    }
+}
+
+impl std::fmt::Debug for CH34x {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CH34x").field("name", &self.name).finish()
+    }
+}
+
+impl DebugProbe for CH34x {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn speed_khz(&self) -> u32 {
+        self.speed_khz
+    }
+
+    fn set_speed(&mut self, speed_khz: u32) -> Result<u32, DebugProbeError> {
+        CH34x::set_speed(self, speed_khz)
+    }
+
+    fn attach(&mut self) -> Result<(), DebugProbeError> {
+        self.ch347_jtag_init()?;
+        self.attached = true;
+        Ok(())
+    }
+
+    fn detach(&mut self) -> Result<(), DebugProbeError> {
+        self.attached = false;
+        Ok(())
+    }
+
+    fn target_reset(&mut self) -> Result<(), DebugProbeError> {
+        self.target_reset_assert()?;
+        self.target_reset_deassert()
+    }
+
+    fn target_reset_assert(&mut self) -> Result<(), DebugProbeError> {
+        self.send_reset(true)
+    }
+
+    fn target_reset_deassert(&mut self) -> Result<(), DebugProbeError> {
+        self.send_reset(false)
+    }
+
+    fn select_protocol(&mut self, protocol: WireProtocol) -> Result<(), DebugProbeError> {
+        match protocol {
+            WireProtocol::Jtag => self.ch347_jtag_init()?,
+            WireProtocol::Swd => self.ch347_swd_init()?,
+        }
+        Ok(())
+    }
+
+    fn active_protocol(&self) -> Option<WireProtocol> {
+        self.protocol
+    }
+
+    fn try_get_interface(
+        self: Box<Self>,
+    ) -> Result<Box<dyn JtagInterface>, (Box<dyn DebugProbe>, DebugProbeError)> {
+        if !self.attached {
+            return Err((self, DebugProbeError::NotAttached));
+        }
+        Ok(self)
+    }
+
+    fn try_get_arm_interface(
+        mut self: Box<Self>,
+    ) -> Result<Box<dyn ArmDebugInterface>, (Box<dyn DebugProbe>, DebugProbeError)> {
+        if !self.attached {
+            return Err((self, DebugProbeError::NotAttached));
+        }
+        if let Err(e) = self.ch347_swd_init() {
+            return Err((self, e));
+        }
+        Ok(self)
+    }
+
+    fn into_probe(self: Box<Self>) -> Box<dyn DebugProbe> {
+        self
+    }
+}
+
+impl CH34x {
+    /// Navigates the TAP into `shift_state` (`ShiftIr`/`ShiftDr`), clocks `bits`
+    /// through it (exiting into `Exit1Ir`/`Exit1Dr` on the last bit), then
+    /// returns to Run-Test/Idle. Decodes TDO only when `read` is set.
+    fn shift(
+        &mut self,
+        bits: &[bool],
+        shift_state: JtagState,
+        exit_state: JtagState,
+        read: bool,
+    ) -> Result<Vec<bool>, DebugProbeError> {
+        let enter = JtagState::navigate(self.tap_state, shift_state);
+        if !enter.is_empty() {
+            self.queue_commands(&jtag::encode_tms(&enter))?;
+            self.flush()?;
+        }
+
+        self.queue_commands(&jtag::encode_shift(bits))?;
+        let raw = self.flush()?;
+        self.tap_state = exit_state;
+
+        let leave = JtagState::navigate(self.tap_state, JtagState::RunTestIdle);
+        self.queue_commands(&jtag::encode_tms(&leave))?;
+        self.flush()?;
+        self.tap_state = JtagState::RunTestIdle;
+
+        Ok(if read {
+            jtag::decode_tdo(&raw, bits.len())
+        } else {
+            Vec::new()
+        })
+    }
+}
+
+impl JtagInterface for CH34x {
+    fn shift_ir(&mut self, bits: &[bool]) -> Result<Vec<bool>, DebugProbeError> {
+        self.shift(bits, JtagState::ShiftIr, JtagState::Exit1Ir, true)
+    }
+
+    fn shift_dr(&mut self, bits: &[bool], read: bool) -> Result<Vec<bool>, DebugProbeError> {
+        self.shift(bits, JtagState::ShiftDr, JtagState::Exit1Dr, read)
+    }
+}
+
+/// Bound on SWD `WAIT` retries in [`CH34x::swd_request`], so a target that's
+/// stuck (rather than merely slow) doesn't spin the caller forever.
+const MAX_SWD_WAIT_RETRIES: u32 = 8;
+
+impl CH34x {
+    fn ch347_swd_init(&mut self) -> Result<(), DebugProbeError> {
+        retry_on_timeout(|| {
+            self.device.write_bulk(
+                self.epout,
+                &[0xD0, 6, 0, 0x02, 9u8, 0x00, 0x00, 0x00, 0x00],
+                Duration::from_millis(500),
+            )
+        })?;
+
+        let mut rev = vec![0; 4];
+        retry_on_timeout(|| {
+            self.device
+                .read_bulk(self.epin, &mut rev, Duration::from_millis(500))
+        })?;
+        log::info!("Swd init response: {:?}", rev);
+
+        self.queue_commands(&swd::encode_bits(&swd::switch_to_swd_bits(), true))?;
+        self.flush()?;
+        self.protocol = Some(WireProtocol::Swd);
+        Ok(())
+    }
+
+    fn swd_ack(&mut self) -> Result<swd::SwdAck, DebugProbeError> {
+        // turnaround: release SWDIO for the target to drive it
+        self.queue_commands(&swd::encode_bits(&[false], false))?;
+        self.flush()?;
+
+        self.queue_commands(&swd::encode_bits(&[false; 3], false))?;
+        let raw = self.flush()?;
+        swd::decode_ack(&swd::decode_bits(&raw, 3))
+    }
+
+    /// Issues the request phase and returns once the target acks `Ok`. Per
+    /// ADIv5, `WAIT` just means the target needs more time and is retried
+    /// up to [`MAX_SWD_WAIT_RETRIES`] times; only `FAULT` (or running out of
+    /// retries) is a hard error.
+    fn swd_request(&mut self, port: SwdPort, read: bool, addr: u8) -> Result<(), DebugProbeError> {
+        for attempt in 0..=MAX_SWD_WAIT_RETRIES {
+            let request = swd::request_bits(port, read, addr);
+            self.queue_commands(&swd::encode_bits(&request, true))?;
+            self.flush()?;
+
+            match self.swd_ack()? {
+                swd::SwdAck::Ok => return Ok(()),
+                swd::SwdAck::Wait => {
+                    if attempt < MAX_SWD_WAIT_RETRIES {
+                        log::warn!(
+                            "SWD WAIT, retrying ({}/{MAX_SWD_WAIT_RETRIES})",
+                            attempt + 1
+                        );
+                    }
+                    // Host was left Hi-Z after the ack phase; release SWDIO
+                    // back to host-driven before resending the request.
+                    self.queue_commands(&swd::encode_bits(&[false], false))?;
+                    self.flush()?;
+                }
+                ack @ swd::SwdAck::Fault => {
+                    return Err(DebugProbeError::Other(format!("SWD fault ack: {ack:?}")));
+                }
+            }
+        }
+
+        Err(DebugProbeError::Other(
+            "SWD WAIT retries exhausted".to_string(),
+        ))
+    }
+
+    fn swd_read(&mut self, port: SwdPort, addr: u8) -> Result<u32, DebugProbeError> {
+        self.swd_request(port, true, addr)?;
+
+        self.queue_commands(&swd::encode_bits(&[false; 33], false))?;
+        let raw = self.flush()?;
+        let value = swd::decode_data(&swd::decode_bits(&raw, 33))?;
+
+        // turnaround: the cycle itself is still Hi-Z, only the bits after it
+        // are host-driven again
+        self.queue_commands(&swd::encode_bits(&[false], false))?;
+        self.flush()?;
+
+        Ok(value)
+    }
+
+    fn swd_write(&mut self, port: SwdPort, addr: u8, value: u32) -> Result<(), DebugProbeError> {
+        self.swd_request(port, false, addr)?;
+
+        // turnaround: the cycle itself is still Hi-Z, only the bits after it
+        // are host-driven again
+        self.queue_commands(&swd::encode_bits(&[false], false))?;
+        self.flush()?;
+
+        self.queue_commands(&swd::encode_bits(&swd::encode_data(value), true))?;
+        self.flush()?;
+
+        Ok(())
+    }
+}
+
+impl ArmDebugInterface for CH34x {
+    fn raw_read_register(&mut self, port: SwdPort, addr: u8) -> Result<u32, DebugProbeError> {
+        self.swd_read(port, addr)
+    }
+
+    fn raw_write_register(
+        &mut self,
+        port: SwdPort,
+        addr: u8,
+        value: u32,
+    ) -> Result<(), DebugProbeError> {
+        self.swd_write(port, addr, value)
+    }
+}
+
+/// Retries `op` on a `TimedOut` error up to `MAX_RETRIES` times, so a
+/// transient USB timeout doesn't abort a long-running flash/debug session;
+/// any other I/O error is mapped straight to a `DebugProbeError`.
+const MAX_RETRIES: u32 = 3;
+
+fn retry_on_timeout<T>(mut op: impl FnMut() -> io::Result<T>) -> Result<T, DebugProbeError> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut && attempt < MAX_RETRIES => {
+                attempt += 1;
+                log::warn!("USB transfer timed out, retrying ({attempt}/{MAX_RETRIES})");
+            }
+            Err(e) => return Err(DebugProbeError::Other(format!("USB transfer error: {e}"))),
+        }
+    }
+}
+
+pub trait InterfaceExt {
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize>;
+}
+
+impl InterfaceExt for Interface {
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> io::Result<usize> {
+        let fut = async {
+            let comp = self.bulk_out(endpoint, buf.to_vec()).await;
+            comp.status.map_err(io::Error::other)?;
+
+            let n = comp.data.actual_length();
+            Ok(n)
+        };
+
+        block_on(fut.or(async {
+            Timer::after(timeout).await;
+            Err(std::io::ErrorKind::TimedOut.into())
+        }))
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let fut = async {
+            let comp = self.bulk_in(endpoint, RequestBuffer::new(buf.len())).await;
+            comp.status.map_err(io::Error::other)?;
+
+            let n = comp.data.len();
+            buf[..n].copy_from_slice(&comp.data);
+            Ok(n)
+        };
+
+        block_on(fut.or(async {
+            Timer::after(timeout).await;
+            Err(std::io::ErrorKind::TimedOut.into())
+        }))
+    }
+}
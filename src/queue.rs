@@ -0,0 +1,156 @@
+//! Pipelines 0xD2 JTAG clock-pair packets across several in-flight USB
+//! transfers instead of one blocking `write_bulk`/`read_bulk` round-trip per
+//! batch.
+//!
+//! [`TransferScheduler`] only enqueues in [`TransferScheduler::queue_commands`]
+//! (draining the oldest transfer only when the in-flight cap is reached);
+//! [`TransferScheduler::flush`] is what actually drives the outstanding
+//! `bulk_out`/`bulk_in` completions and hands back the concatenated TDO bytes,
+//! in submission order.
+
+use crate::PACK;
+use nusb::transfer::RequestBuffer;
+use nusb::{Interface, Queue};
+use probe_rs::probe::DebugProbeError;
+use smol::future::FutureExt;
+use smol::Timer;
+use std::time::Duration;
+
+/// Max clock-pair bytes per 0xD2 packet in standard pack mode.
+const STANDARD_PACK_SIZE: usize = 510;
+/// Max clock-pair bytes per 0xD2 packet in larger pack mode.
+const LARGER_PACK_SIZE: usize = 4086;
+/// Bound on outstanding bulk_out/bulk_in pairs, so a long scan chain can't
+/// grow the in-flight queue without limit.
+const MAX_IN_FLIGHT: usize = 8;
+/// Per-completion timeout in [`TransferScheduler::drain_to_depth`], mirroring
+/// `InterfaceExt`'s bulk transfer timeout.
+const COMPLETION_TIMEOUT: Duration = Duration::from_millis(500);
+/// Bound on completion-timeout retries, so a wedged USB transfer doesn't spin
+/// `drain_to_depth` forever.
+const MAX_COMPLETION_RETRIES: u32 = 3;
+
+pub(crate) struct TransferScheduler {
+    pack_size: usize,
+    out_queue: Queue<Vec<u8>>,
+    in_queue: Queue<RequestBuffer>,
+    in_flight: usize,
+    results: Vec<u8>,
+}
+
+impl TransferScheduler {
+    pub(crate) fn new(device: &Interface, epout: u8, epin: u8, pack: Option<&PACK>) -> Self {
+        let pack_size = match pack {
+            Some(PACK::LARGER_PACK) => LARGER_PACK_SIZE,
+            _ => STANDARD_PACK_SIZE,
+        };
+
+        Self {
+            pack_size,
+            out_queue: device.bulk_out_queue(epout),
+            in_queue: device.bulk_in_queue(epin),
+            in_flight: 0,
+            results: Vec::new(),
+        }
+    }
+
+    /// Splits `bytes` into `pack_size`-sized chunks, wraps each in the 0xD2
+    /// framing, and submits it along with a matching bulk-in read. It does not
+    /// wait for these transfers; it only waits for the oldest outstanding one
+    /// when [`MAX_IN_FLIGHT`] transfers are already in flight.
+    pub(crate) fn queue_commands(&mut self, bytes: &[u8]) -> Result<(), DebugProbeError> {
+        for chunk in bytes.chunks(self.pack_size.max(1)) {
+            let len = chunk.len() as u16;
+            let mut out = vec![0xd2u8];
+            out.push((len & 0xFF) as u8);
+            out.push((len >> 8) as u8);
+            out.extend_from_slice(chunk);
+
+            self.drain_to_depth(MAX_IN_FLIGHT - 1)?;
+            self.out_queue.submit(out);
+            // One TDO byte comes back per clocked bit, i.e. per clock pair.
+            self.in_queue.submit(RequestBuffer::new(chunk.len() / 2));
+            self.in_flight += 1;
+        }
+        Ok(())
+    }
+
+    /// Drains completions until at most `depth` transfers remain in flight,
+    /// folding the TDO bytes of each into `results` in submission order. A
+    /// failed completion (timeout, unplug) is propagated rather than
+    /// panicking, so a transient USB hiccup during a long flash/debug session
+    /// doesn't abort the whole process.
+    fn drain_to_depth(&mut self, depth: usize) -> Result<(), DebugProbeError> {
+        while self.in_flight > depth {
+            self.next_out_complete()
+                .map_err(|e| DebugProbeError::Other(format!("queued send error: {e}")))?;
+            let data = self
+                .next_in_complete()
+                .map_err(|e| DebugProbeError::Other(format!("queued read error: {e}")))?;
+            self.results.extend_from_slice(&data);
+            self.in_flight -= 1;
+        }
+        Ok(())
+    }
+
+    /// Waits for the next queued bulk-out completion, retrying on a completion
+    /// timeout up to [`MAX_COMPLETION_RETRIES`] times rather than blocking
+    /// forever on a wedged transfer.
+    fn next_out_complete(&mut self) -> Result<(), String> {
+        let mut attempt = 0;
+        loop {
+            let fut = async {
+                let completion = self.out_queue.next_complete().await;
+                completion
+                    .into_result()
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            };
+            match smol::block_on(fut.or(async {
+                Timer::after(COMPLETION_TIMEOUT).await;
+                Err("timed out".to_string())
+            })) {
+                Ok(()) => return Ok(()),
+                Err(e) if e == "timed out" && attempt < MAX_COMPLETION_RETRIES => {
+                    attempt += 1;
+                    log::warn!(
+                        "queued send completion timed out, retrying ({attempt}/{MAX_COMPLETION_RETRIES})"
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Waits for the next queued bulk-in completion, same timeout/retry
+    /// policy as [`TransferScheduler::next_out_complete`].
+    fn next_in_complete(&mut self) -> Result<Vec<u8>, String> {
+        let mut attempt = 0;
+        loop {
+            let fut = async {
+                let completion = self.in_queue.next_complete().await;
+                completion.into_result().map_err(|e| e.to_string())
+            };
+            match smol::block_on(fut.or(async {
+                Timer::after(COMPLETION_TIMEOUT).await;
+                Err("timed out".to_string())
+            })) {
+                Ok(data) => return Ok(data),
+                Err(e) if e == "timed out" && attempt < MAX_COMPLETION_RETRIES => {
+                    attempt += 1;
+                    log::warn!(
+                        "queued read completion timed out, retrying ({attempt}/{MAX_COMPLETION_RETRIES})"
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Drives every outstanding completion and returns the concatenated TDO
+    /// bytes accumulated since the last flush.
+    pub(crate) fn flush(&mut self) -> Result<Vec<u8>, DebugProbeError> {
+        self.drain_to_depth(0)?;
+        Ok(std::mem::take(&mut self.results))
+    }
+}
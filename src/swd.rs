@@ -0,0 +1,173 @@
+//! SWD bit-level encoding: the line-reset/JTAG-to-SWD switch sequence, the
+//! 8-bit request packet, and the ack/data/parity framing of a transaction.
+//!
+//! Like `jtag`, this module only knows about bits and the CH347's two-byte
+//! clock-pair encoding; `CH34x::swd_read`/`swd_write` own the USB round-trips.
+
+use probe_rs::probe::DebugProbeError;
+
+/// `DP` selects the Debug Port, `AP` the currently selected Access Port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SwdPort {
+    Dp,
+    Ap,
+}
+
+/// The 3-bit ACK phase of an SWD transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SwdAck {
+    Ok,
+    Wait,
+    Fault,
+}
+
+impl SwdAck {
+    fn decode(bits: &[bool]) -> Result<Self, DebugProbeError> {
+        match bits {
+            [true, false, false] => Ok(SwdAck::Ok),
+            [false, true, false] => Ok(SwdAck::Wait),
+            [false, false, true] => Ok(SwdAck::Fault),
+            _ => Err(DebugProbeError::Other(format!("invalid SWD ack {bits:?}"))),
+        }
+    }
+}
+
+/// The magic 16-bit pattern (sent LSB-first) that switches CH347's SWDIO/SWCLK
+/// lines out of JTAG mode and into SWD mode, per ARM's line-reset sequence.
+const JTAG_TO_SWD_SEQUENCE: u16 = 0xE79E;
+
+/// Builds the SWDIO bit sequence for the JTAG-to-SWD line reset: >=50 cycles
+/// with SWDIO high, the 16-bit magic sequence, another line reset, then a
+/// couple of idle cycles before the first transaction.
+pub(crate) fn switch_to_swd_bits() -> Vec<bool> {
+    let mut bits = Vec::with_capacity(50 + 16 + 50 + 2);
+    bits.extend(std::iter::repeat(true).take(50));
+    bits.extend((0..16).map(|i| (JTAG_TO_SWD_SEQUENCE >> i) & 1 != 0));
+    bits.extend(std::iter::repeat(true).take(50));
+    bits.extend(std::iter::repeat(false).take(2));
+    bits
+}
+
+/// One clocked SWD cycle. SWDIO shares the JTAG TDI wire position (bit 4);
+/// `output` marks whether the host drives SWDIO this cycle or releases the
+/// (bidirectional) line for the target to drive, e.g. during turnaround and ack.
+fn encode_bit(swdio: bool, output: bool) -> (u8, u8) {
+    let left = (u8::from(swdio) << 4) | (u8::from(output) << 5);
+    (left, left | 0x01)
+}
+
+/// Encodes a bit sequence into the two-byte TCK-low/TCK-high clock pairs `send`
+/// expects, holding the line at `output` for every cycle.
+pub(crate) fn encode_bits(bits: &[bool], output: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bits.len() * 2);
+    for &swdio in bits {
+        let (low, high) = encode_bit(swdio, output);
+        out.push(low);
+        out.push(high);
+    }
+    out
+}
+
+/// Decodes the SWDIO sample (bit 4) out of the bulk-in bytes `send` returned
+/// for `bit_count` clocked cycles.
+pub(crate) fn decode_bits(raw: &[u8], bit_count: usize) -> Vec<bool> {
+    raw.iter()
+        .take(bit_count)
+        .map(|&b| (b >> 4) & 0x01 != 0)
+        .collect()
+}
+
+fn parity_of(bits: &[bool]) -> bool {
+    bits.iter().filter(|&&b| b).count() % 2 != 0
+}
+
+/// The 8-bit SWD request packet: start, APnDP, RnW, A[2:3], parity, stop, park.
+pub(crate) fn request_bits(port: SwdPort, read: bool, addr: u8) -> [bool; 8] {
+    let apndp = port == SwdPort::Ap;
+    let a2 = (addr >> 2) & 0x01 != 0;
+    let a3 = (addr >> 3) & 0x01 != 0;
+    let parity = parity_of(&[apndp, read, a2, a3]);
+    [true, apndp, read, a2, a3, parity, false, true]
+}
+
+/// The 33-bit data phase of a write: 32 bits of `value` (LSB-first) plus the
+/// trailing parity bit.
+pub(crate) fn encode_data(value: u32) -> [bool; 33] {
+    let mut bits = [false; 33];
+    for (i, bit) in bits.iter_mut().take(32).enumerate() {
+        *bit = (value >> i) & 1 != 0;
+    }
+    bits[32] = parity_of(&bits[..32]);
+    bits
+}
+
+/// Decodes the 33-bit data phase of a read back into a value, checking parity.
+pub(crate) fn decode_data(bits: &[bool]) -> Result<u32, DebugProbeError> {
+    if bits.len() != 33 {
+        return Err(DebugProbeError::Other(format!(
+            "expected 33 SWD data bits, got {}",
+            bits.len()
+        )));
+    }
+
+    let value = bits[..32]
+        .iter()
+        .enumerate()
+        .fold(0u32, |acc, (i, &bit)| acc | (u32::from(bit) << i));
+
+    if parity_of(&bits[..32]) != bits[32] {
+        return Err(DebugProbeError::Other("SWD data parity error".into()));
+    }
+
+    Ok(value)
+}
+
+pub(crate) fn decode_ack(bits: &[bool]) -> Result<SwdAck, DebugProbeError> {
+    SwdAck::decode(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_bits_have_even_parity_over_apndp_rw_a2_a3() {
+        for port in [SwdPort::Dp, SwdPort::Ap] {
+            for read in [true, false] {
+                for addr in [0x0u8, 0x4, 0x8, 0xC] {
+                    let bits = request_bits(port, read, addr);
+                    assert!(bits[0], "start bit must be set");
+                    assert!(!bits[6], "stop bit must be 0");
+                    assert!(bits[7], "park bit must be set");
+
+                    let fields = [bits[1], bits[2], bits[3], bits[4]];
+                    assert_eq!(bits[5], parity_of(&fields));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn data_round_trips_through_encode_and_decode() {
+        for value in [0u32, 1, 0xDEAD_BEEF, u32::MAX] {
+            let bits = encode_data(value);
+            assert_eq!(decode_data(&bits).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn decode_data_rejects_bad_parity() {
+        let mut bits = encode_data(0x1234_5678);
+        let last = bits.len() - 1;
+        bits[last] = !bits[last];
+        assert!(decode_data(&bits).is_err());
+    }
+
+    #[test]
+    fn decode_ack_maps_the_three_valid_patterns() {
+        assert_eq!(decode_ack(&[true, false, false]).unwrap(), SwdAck::Ok);
+        assert_eq!(decode_ack(&[false, true, false]).unwrap(), SwdAck::Wait);
+        assert_eq!(decode_ack(&[false, false, true]).unwrap(), SwdAck::Fault);
+        assert!(decode_ack(&[true, true, true]).is_err());
+    }
+}